@@ -1,14 +1,105 @@
 //! Helper to run external commands.
+//!
+//! Supports an opt-in result cache for slow, idempotent commands (package-manager
+//! listings, `git` metadata, fact probes) so repeated runs don't have to re-spawn
+//! them every time.
 
-use std::process;
+use crate::Config;
+use failure::{bail, format_err, Error};
+use serde_derive::{Deserialize, Serialize};
 use std::borrow::Cow;
-use failure::{Error, bail};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
 use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Disambiguates cache temp file names between concurrent writers in the
+/// same process that happen to share a PID and land on the same cache key.
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Background `stale_ok()` refresh threads that haven't finished yet.
+///
+/// quickcfg is a one-shot CLI: a refresh spawned late in a run would
+/// otherwise still be running when the process exits and get abandoned
+/// mid-write, so the cache would never actually catch up. Callers that use
+/// `stale_ok()` must call [`join_pending_refreshes`] once before exiting.
+static PENDING_REFRESHES: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Block until every in-flight `stale_ok()` background refresh has finished
+/// writing its cache entry.
+pub fn join_pending_refreshes() {
+    let handles: Vec<_> = match PENDING_REFRESHES.lock() {
+        Ok(mut pending) => pending.drain(..).collect(),
+        Err(_) => return,
+    };
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+/// A cached record of a previous invocation, as stored on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheRecord {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Whether the command exited successfully. A failed command is never
+    /// served from the cache, but we keep the field so the on-disk format is
+    /// self-describing.
+    success: bool,
+    /// When this record was captured.
+    captured: SystemTime,
+}
+
+impl CacheRecord {
+    fn into_lines(self) -> Result<Vec<String>, Error> {
+        split_lines(&self.stdout)
+    }
+}
+
+/// Cache configuration attached to a [`Command`].
+#[derive(Debug, Clone)]
+struct CacheOptions {
+    dir: PathBuf,
+    ttl: Duration,
+    stale_ok: bool,
+    force: bool,
+    env_keys: Vec<String>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            dir: default_cache_dir(),
+            ttl: Duration::from_secs(0),
+            stale_ok: false,
+            force: false,
+            env_keys: Vec::new(),
+        }
+    }
+}
+
+/// Default location for cached command output.
+///
+/// Callers that have access to a `Config` or `opts` should override this with
+/// [`Command::cache_dir`], pointing it at the state directory instead.
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from(".cache/quickcfg/command")
+}
 
 /// A command wrapper that simplifies interaction with external commands.
 #[derive(Debug)]
 pub struct Command<'a> {
     name: Cow<'a, str>,
+    cache: Option<CacheOptions>,
 }
 
 impl<'a> Command<'a> {
@@ -16,23 +107,442 @@ impl<'a> Command<'a> {
     pub fn new(name: impl Into<Cow<'a, str>>) -> Command<'a> {
         Command {
             name: name.into(),
+            cache: None,
         }
     }
 
+    /// Cache the result of this command for the given TTL.
+    pub fn cached(mut self, ttl: Duration) -> Self {
+        self.cache.get_or_insert_with(CacheOptions::default).ttl = ttl;
+        self
+    }
+
+    /// Enable caching using the directory and TTL configured in `config`.
+    pub fn configured(self, config: &Config) -> Self {
+        self.cached(config.command_cache.ttl)
+            .cache_dir(config.command_cache.dir.clone())
+    }
+
+    /// Override the directory cached results are stored under.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache.get_or_insert_with(CacheOptions::default).dir = dir.into();
+        self
+    }
+
+    /// Allow serving an expired cache entry immediately while a refresh runs
+    /// in the background for the next invocation.
+    pub fn stale_ok(mut self) -> Self {
+        self.cache
+            .get_or_insert_with(CacheOptions::default)
+            .stale_ok = true;
+        self
+    }
+
+    /// Bypass the cache for this call, always running the command and
+    /// overwriting any existing entry.
+    pub fn force(mut self) -> Self {
+        self.cache.get_or_insert_with(CacheOptions::default).force = true;
+        self
+    }
+
+    /// Declare environment variables that are relevant to this command's
+    /// output, so they are mixed into the cache key.
+    pub fn cache_env(mut self, vars: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.cache
+            .get_or_insert_with(CacheOptions::default)
+            .env_keys
+            .extend(vars.into_iter().map(Into::into));
+        self
+    }
+
     /// Run the given command, return all lines printed to stdout on success.
     pub fn run_lines<S>(&self, args: impl IntoIterator<Item = S>) -> Result<Vec<String>, Error>
-        where
-            S: AsRef<OsStr>
+    where
+        S: AsRef<OsStr>,
     {
+        let args: Vec<String> = args
+            .into_iter()
+            .map(|a| a.as_ref().to_string_lossy().into_owned())
+            .collect();
+
+        match &self.cache {
+            Some(cache) => self.run_lines_cached(cache, args),
+            None => self.run_lines_uncached(&args),
+        }
+    }
+
+    fn run_lines_uncached(&self, args: &[String]) -> Result<Vec<String>, Error> {
+        self.capture(args)?.into_lines()
+    }
+
+    fn run_lines_cached(
+        &self,
+        cache: &CacheOptions,
+        args: Vec<String>,
+    ) -> Result<Vec<String>, Error> {
+        let cwd = env::current_dir()?;
+        let key = self.cache_key(&args, &cwd, &cache.env_keys);
+        let path = cache.dir.join(format!("{}.json", key));
+
+        if !cache.force {
+            if let Some(record) = read_record(&path) {
+                let age = record
+                    .captured
+                    .elapsed()
+                    .unwrap_or_else(|_| Duration::from_secs(0));
+
+                if age < cache.ttl {
+                    return record.into_lines();
+                }
+
+                if cache.stale_ok {
+                    let lines = record.clone().into_lines();
+                    self.refresh_in_background(args, path);
+                    return lines;
+                }
+            }
+        }
+
+        let record = self.capture(&args)?;
+        write_record(&path, &record)?;
+        record.into_lines()
+    }
+
+    /// Re-run the command on a background thread and overwrite the cache
+    /// entry, without blocking the current, stale-served call.
+    ///
+    /// The handle is kept in [`PENDING_REFRESHES`] so [`join_pending_refreshes`]
+    /// can wait for it before the process exits.
+    fn refresh_in_background(&self, args: Vec<String>, path: PathBuf) {
+        let name = self.name.clone().into_owned();
+
+        let handle = std::thread::spawn(move || {
+            let cmd = Command::new(name);
+            if let Ok(record) = cmd.capture(&args) {
+                let _ = write_record(&path, &record);
+            }
+        });
+
+        if let Ok(mut pending) = PENDING_REFRESHES.lock() {
+            pending.push(handle);
+        }
+    }
+
+    /// Actually spawn the process and collect its output into a record.
+    ///
+    /// A non-zero exit is never treated as a cacheable success.
+    fn capture(&self, args: &[String]) -> Result<CacheRecord, Error> {
         let mut cmd = process::Command::new(self.name.as_ref());
         cmd.args(args);
         let output = cmd.output()?;
 
         if !output.status.success() {
-            bail!("Command exited with non-zero status: {:?}: {:?}", cmd, output.status);
+            bail!(
+                "Command exited with non-zero status: {:?}: {:?}",
+                cmd,
+                output.status
+            );
         }
 
-        let lines = std::str::from_utf8(&output.stdout)?.split("\n").map(|s| s.to_string()).collect();
-        Ok(lines)
+        Ok(CacheRecord {
+            stdout: output.stdout,
+            stderr: output.stderr,
+            success: true,
+            captured: SystemTime::now(),
+        })
+    }
+
+    /// Derive a cache key from the resolved binary, its arguments, the
+    /// current working directory, and any declared environment variables.
+    fn cache_key(&self, args: &[String], cwd: &Path, env_keys: &[String]) -> String {
+        let mut hasher = DefaultHasher::new();
+        resolve_binary(&self.name).hash(&mut hasher);
+        args.hash(&mut hasher);
+        cwd.hash(&mut hasher);
+
+        let mut env_pairs: Vec<(String, String)> = env_keys
+            .iter()
+            .map(|key| (key.clone(), env::var(key).unwrap_or_default()))
+            .collect();
+        env_pairs.sort();
+        env_pairs.hash(&mut hasher);
+
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Resolve `name` to an absolute path, searching `PATH` if it isn't already
+/// one, so that changes to `PATH` invalidate the cache key.
+fn resolve_binary(name: &str) -> PathBuf {
+    let candidate = Path::new(name);
+
+    if candidate.components().count() > 1 {
+        return fs::canonicalize(candidate).unwrap_or_else(|_| candidate.to_path_buf());
+    }
+
+    if let Some(paths) = env::var_os("PATH") {
+        for dir in env::split_paths(&paths) {
+            let full = dir.join(name);
+            if full.is_file() {
+                return fs::canonicalize(&full).unwrap_or(full);
+            }
+        }
+    }
+
+    candidate.to_path_buf()
+}
+
+fn split_lines(stdout: &[u8]) -> Result<Vec<String>, Error> {
+    let lines = std::str::from_utf8(stdout)?
+        .split("\n")
+        .map(|s| s.to_string())
+        .collect();
+    Ok(lines)
+}
+
+fn read_record(path: &Path) -> Option<CacheRecord> {
+    let file = File::open(path).ok()?;
+    serde_json::from_reader(file).ok()
+}
+
+/// Write a cache record atomically: write to a temporary file in the same
+/// directory, then rename it into place, so concurrent readers never observe
+/// a partially written record.
+fn write_record(path: &Path, record: &CacheRecord) -> Result<(), Error> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| format_err!("cache path `{}` has no parent directory", path.display()))?;
+    fs::create_dir_all(dir)?;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format_err!("cache path `{}` has no file name", path.display()))?
+        .to_string_lossy();
+    // PID alone isn't enough: two writers in the same process (e.g. a
+    // stale-while-revalidate background refresh racing a foreground miss for
+    // the same cache key) would otherwise collide on the same temp path.
+    let unique = NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = dir.join(format!(
+        ".{}.{}.{:?}.{}.tmp",
+        file_name,
+        process::id(),
+        std::thread::current().id(),
+        unique
+    ));
+
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(record)?)?;
+        tmp.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Set up an isolated temporary directory for a test, removing any
+    /// leftovers from a previous run.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("quickcfg-command-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create test dir");
+        dir
+    }
+
+    /// Args for a shell one-liner that appends a byte to `counter` every
+    /// time it actually runs, then prints the file's contents - so the
+    /// output directly reveals how many times the command was executed.
+    fn counter_script(counter: &Path) -> Vec<String> {
+        vec![
+            "-c".to_string(),
+            format!("printf x >> {0}; cat {0}", counter.display()),
+        ]
+    }
+
+    #[test]
+    fn test_cache_hit_within_ttl() {
+        let dir = test_dir("hit");
+        let counter = dir.join("counter");
+        let cache_dir = dir.join("cache");
+
+        let first = Command::new("sh")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir.clone())
+            .run_lines(counter_script(&counter))
+            .expect("first run");
+        assert_eq!(first, vec!["x".to_string()]);
+
+        let second = Command::new("sh")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir)
+            .run_lines(counter_script(&counter))
+            .expect("second run");
+        assert_eq!(
+            second,
+            vec!["x".to_string()],
+            "a fresh entry must be served without re-running the command"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_cache_expires_after_ttl() {
+        let dir = test_dir("expire");
+        let counter = dir.join("counter");
+        let cache_dir = dir.join("cache");
+
+        Command::new("sh")
+            .cached(Duration::from_millis(10))
+            .cache_dir(cache_dir.clone())
+            .run_lines(counter_script(&counter))
+            .expect("first run");
+
+        thread::sleep(Duration::from_millis(50));
+
+        let second = Command::new("sh")
+            .cached(Duration::from_millis(10))
+            .cache_dir(cache_dir)
+            .run_lines(counter_script(&counter))
+            .expect("second run");
+        assert_eq!(
+            second,
+            vec!["xx".to_string()],
+            "an expired entry must cause the command to re-run"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_force_bypasses_a_fresh_cache_entry() {
+        let dir = test_dir("force");
+        let counter = dir.join("counter");
+        let cache_dir = dir.join("cache");
+
+        Command::new("sh")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir.clone())
+            .run_lines(counter_script(&counter))
+            .expect("first run");
+
+        let second = Command::new("sh")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir)
+            .force()
+            .run_lines(counter_script(&counter))
+            .expect("forced run");
+        assert_eq!(
+            second,
+            vec!["xx".to_string()],
+            "force() must bypass an otherwise-fresh cache entry"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_failed_command_is_never_cached() {
+        let dir = test_dir("failed");
+        let cache_dir = dir.join("cache");
+
+        let result = Command::new("false")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir.clone())
+            .run_lines(Vec::<String>::new());
+        assert!(result.is_err());
+
+        let left_a_cache_entry = fs::read_dir(&cache_dir)
+            .map(|mut entries| entries.next().is_some())
+            .unwrap_or(false);
+        assert!(
+            !left_a_cache_entry,
+            "a non-zero exit must never be written to the cache"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stale_ok_serves_stale_then_refreshes_in_background() {
+        let dir = test_dir("stale");
+        let counter = dir.join("counter");
+        let cache_dir = dir.join("cache");
+
+        Command::new("sh")
+            .cached(Duration::from_millis(10))
+            .cache_dir(cache_dir.clone())
+            .run_lines(counter_script(&counter))
+            .expect("first run");
+
+        thread::sleep(Duration::from_millis(50));
+
+        let stale = Command::new("sh")
+            .cached(Duration::from_millis(10))
+            .cache_dir(cache_dir.clone())
+            .stale_ok()
+            .run_lines(counter_script(&counter))
+            .expect("stale run");
+        assert_eq!(
+            stale,
+            vec!["x".to_string()],
+            "an expired entry must still be served immediately when stale_ok() is set"
+        );
+
+        join_pending_refreshes();
+
+        let refreshed = Command::new("sh")
+            .cached(Duration::from_secs(60))
+            .cache_dir(cache_dir)
+            .run_lines(counter_script(&counter))
+            .expect("run after the background refresh completed");
+        assert_eq!(
+            refreshed,
+            vec!["xx".to_string()],
+            "the background refresh must have updated the cache entry"
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_concurrent_writers_never_corrupt_the_record() {
+        let dir = test_dir("concurrent");
+        let cache_dir = dir.join("cache");
+        fs::create_dir_all(&cache_dir).expect("create cache dir");
+
+        let path = cache_dir.join("race.json");
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let record = CacheRecord {
+                        stdout: format!("writer {}", i).into_bytes(),
+                        stderr: Vec::new(),
+                        success: true,
+                        captured: SystemTime::now(),
+                    };
+                    write_record(&path, &record).expect("write_record");
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("writer thread panicked");
+        }
+
+        // Whichever writer won last, the file on disk must always be a
+        // complete, valid record - never a half-written or truncated one.
+        let file = File::open(&path).expect("cache file exists");
+        let _: CacheRecord = serde_json::from_reader(file).expect("valid JSON record");
+
+        fs::remove_dir_all(&dir).ok();
     }
 }
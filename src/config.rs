@@ -0,0 +1,43 @@
+//! Top-level configuration.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Defaults for `Command`'s result cache, so callers don't have to pick a
+/// directory or TTL themselves.
+#[derive(Debug, Clone)]
+pub struct CommandCacheConfig {
+    /// Directory cached command output is stored under.
+    pub dir: PathBuf,
+    /// Default time-to-live for a cached entry.
+    pub ttl: Duration,
+}
+
+/// Top-level configuration, rooted at the project's state directory.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Directory used to store state, caches, and other runtime data.
+    pub state_dir: PathBuf,
+    /// Defaults for `Command`'s result cache.
+    pub command_cache: CommandCacheConfig,
+}
+
+impl Config {
+    /// Construct the default configuration rooted at `state_dir`.
+    pub fn new(state_dir: impl Into<PathBuf>) -> Self {
+        let state_dir = state_dir.into();
+
+        Config {
+            command_cache: CommandCacheConfig {
+                dir: state_dir.join("command-cache"),
+                ttl: Duration::from_secs(15 * 60),
+            },
+            state_dir,
+        }
+    }
+
+    /// Path to the state file tracked by `DiskState`.
+    pub fn state_path(&self) -> PathBuf {
+        self.state_dir.join("state.json")
+    }
+}
@@ -3,19 +3,46 @@ use crate::{environment as e, facts::Facts, Template};
 use failure::{bail, format_err, Error};
 use serde::Deserialize;
 use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
 use std::env;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const HEADER: &'static str = "quickcfg:";
+/// Directive pulling in one or more other files as lower-priority layers.
+const INCLUDE: &'static str = "include";
+/// Directive masking an inherited key from lower-priority layers.
+const UNSET: &'static str = "unset";
+
+/// A single resolved layer of hierarchy data.
+#[derive(Debug)]
+struct Layer {
+    mapping: Mapping,
+    /// Keys that this layer unsets, masking the same key in any
+    /// lower-priority (later) layer.
+    unset: HashSet<String>,
+}
 
 /// Wrapper for hierarchy data.
-pub struct Data(Vec<Mapping>);
+pub struct Data(Vec<Layer>);
 
 impl Data {
     /// Construct a new set of hierarchical data.
     pub fn new(data: impl IntoIterator<Item = Mapping>) -> Self {
-        Data(data.into_iter().collect())
+        Data(
+            data.into_iter()
+                .map(|mapping| Layer {
+                    mapping,
+                    unset: HashSet::new(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Construct from already-resolved layers, as produced by the hierarchy
+    /// loader after processing `include` and `unset` directives.
+    fn from_layers(layers: Vec<Layer>) -> Self {
+        Data(layers)
     }
 
     /// Load the given key.
@@ -23,12 +50,16 @@ impl Data {
     where
         T: Deserialize<'de>,
     {
-        let key = serde_yaml::Value::String(key.to_string());
+        let k = serde_yaml::Value::String(key.to_string());
 
-        for m in &self.0 {
-            if let Some(value) = m.get(&key) {
+        for layer in &self.0 {
+            if let Some(value) = layer.mapping.get(&k) {
                 return Ok(Some(T::deserialize(value.clone())?));
             }
+
+            if layer.unset.contains(key) {
+                return Ok(None);
+            }
         }
 
         Ok(None)
@@ -47,14 +78,18 @@ impl Data {
     where
         T: Deserialize<'de>,
     {
-        let key = serde_yaml::Value::String(key.to_string());
+        let k = serde_yaml::Value::String(key.to_string());
 
         let mut out = Vec::new();
 
-        for m in &self.0 {
-            if let Some(value) = m.get(&key) {
+        for layer in &self.0 {
+            if let Some(value) = layer.mapping.get(&k) {
                 out.extend(<Vec<T> as Deserialize>::deserialize(value.clone())?);
             }
+
+            if layer.unset.contains(key) {
+                break;
+            }
         }
 
         Ok(out)
@@ -133,29 +168,123 @@ pub fn load<'a>(
 
         let path = path.to_path(root);
 
-        let map = load_mapping(&path)
+        let mut stack = HashSet::new();
+        let layers = load_layers(&path, &mut stack)
             .map_err(|e| format_err!("failed to load: {}: {}", path.display(), e))?;
 
-        stages.push(map);
+        stages.extend(layers);
     }
 
-    return Ok(Data(stages));
+    Ok(Data::from_layers(stages))
+}
 
-    /// Extend the existing mapping from the given hierarchy.
-    fn load_mapping(path: &Path) -> Result<serde_yaml::Mapping, Error> {
-        use serde_yaml::Value;
+/// Load the given file, resolving `include` and `unset` directives into a
+/// sequence of layers.
+///
+/// The including file's own layer is placed before its includes, so they
+/// act as lower-priority fallbacks, since `Data` looks up keys in layer
+/// order and returns the first match. `stack` tracks the canonicalized
+/// paths currently being resolved, so an include cycle is reported instead
+/// of recursing forever; an empty `stack` means `path` is the top-level
+/// hierarchy file rather than something pulled in via `include`, which is
+/// reflected in the error message if it can't be found.
+fn load_layers(path: &Path, stack: &mut HashSet<PathBuf>) -> Result<Vec<Layer>, Error> {
+    let is_top_level = stack.is_empty();
+
+    let canonical = path.canonicalize().map_err(|e| {
+        if is_top_level {
+            format_err!("hierarchy file not found: {}: {}", path.display(), e)
+        } else {
+            format_err!("included file is missing: {}: {}", path.display(), e)
+        }
+    })?;
 
-        let file = match File::open(&path) {
-            Ok(file) => file,
-            Err(e) => match e.kind() {
-                _ => bail!("failed to open file: {}", e),
-            },
-        };
+    if !stack.insert(canonical.clone()) {
+        bail!("include cycle detected while resolving: {}", path.display());
+    }
+
+    let result = load_layers_inner(path, stack);
+    stack.remove(&canonical);
+    result
+}
+
+fn load_layers_inner(path: &Path, stack: &mut HashSet<PathBuf>) -> Result<Vec<Layer>, Error> {
+    let mut mapping = load_mapping(path)?;
+
+    let includes = extract_include_paths(&mut mapping)?;
+    let unset = extract_unset_keys(&mut mapping)?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
 
-        match serde_yaml::from_reader(file)? {
-            Value::Mapping(m) => return Ok(m),
-            _ => bail!("exists, but is not a mapping"),
+    let mut layers = vec![Layer { mapping, unset }];
+
+    for include in includes {
+        layers.extend(load_layers(&dir.join(include), stack)?);
+    }
+
+    Ok(layers)
+}
+
+/// Parse the given file as a YAML mapping.
+fn load_mapping(path: &Path) -> Result<Mapping, Error> {
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => match e.kind() {
+            _ => bail!("failed to open file: {}", e),
+        },
+    };
+
+    match serde_yaml::from_reader(file)? {
+        Value::Mapping(m) => Ok(m),
+        _ => bail!("exists, but is not a mapping"),
+    }
+}
+
+/// Pull the `include` directive out of a mapping, accepting either a single
+/// path or a list of paths.
+fn extract_include_paths(map: &mut Mapping) -> Result<Vec<String>, Error> {
+    let key = Value::String(INCLUDE.to_string());
+
+    match map.remove(&key) {
+        None => Ok(Vec::new()),
+        Some(Value::String(path)) => Ok(vec![path]),
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|v| match v {
+                Value::String(path) => Ok(path),
+                other => bail!("bad `include` entry, expected a string: {:?}", other),
+            })
+            .collect(),
+        Some(other) => bail!(
+            "bad `include` directive, expected a string or list of strings: {:?}",
+            other
+        ),
+    }
+}
+
+/// Pull the `unset` directive out of a mapping, accepting either a single
+/// key or a list of keys.
+fn extract_unset_keys(map: &mut Mapping) -> Result<HashSet<String>, Error> {
+    let key = Value::String(UNSET.to_string());
+
+    match map.remove(&key) {
+        None => Ok(HashSet::new()),
+        Some(Value::String(key)) => {
+            let mut set = HashSet::new();
+            set.insert(key);
+            Ok(set)
         }
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|v| match v {
+                Value::String(key) => Ok(key),
+                other => bail!("bad `unset` entry, expected a string: {:?}", other),
+            })
+            .collect(),
+        Some(other) => bail!(
+            "bad `unset` directive, expected a string or list of strings: {:?}",
+            other
+        ),
     }
 }
 
@@ -163,6 +292,7 @@ pub fn load<'a>(
 mod tests {
     use super::Data;
     use serde_yaml::{Mapping, Value};
+    use std::collections::HashSet;
 
     #[test]
     fn test_hiera_lookup() {
@@ -198,4 +328,105 @@ mod tests {
             vec![String::from("item1"), String::from("item2")],
         );
     }
+
+    #[test]
+    fn test_unset_masks_lower_priority_layers() {
+        let layer1 = Mapping::new();
+        let mut layer2 = Mapping::new();
+
+        layer2.insert("foo".into(), "inherited value".into());
+
+        let data = Data(vec![
+            super::Layer {
+                mapping: layer1,
+                unset: vec![String::from("foo")].into_iter().collect(),
+            },
+            super::Layer {
+                mapping: layer2,
+                unset: Default::default(),
+            },
+        ]);
+
+        assert_eq!(data.load::<String>("foo").expect("masked key"), None,);
+    }
+
+    /// Set up an isolated temporary directory for a loader test, removing
+    /// any leftovers from a previous run.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "quickcfg-hierarchy-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create test dir");
+        dir
+    }
+
+    #[test]
+    fn test_load_layers_include_is_lower_priority_than_own_keys() {
+        let dir = test_dir("include-priority");
+
+        std::fs::write(dir.join("common.yaml"), "foo: generic\nbar: from common\n")
+            .expect("write common.yaml");
+        std::fs::write(
+            dir.join("host.yaml"),
+            "include: common.yaml\nfoo: specific\n",
+        )
+        .expect("write host.yaml");
+
+        let layers = super::load_layers(&dir.join("host.yaml"), &mut HashSet::new())
+            .expect("load host.yaml");
+        let data = Data(layers);
+
+        assert_eq!(
+            data.load::<String>("foo").expect("own key wins"),
+            Some("specific".into()),
+        );
+        assert_eq!(
+            data.load::<String>("bar").expect("fall back to include"),
+            Some("from common".into()),
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_load_layers_detects_include_cycle() {
+        let dir = test_dir("include-cycle");
+
+        std::fs::write(dir.join("a.yaml"), "include: b.yaml\n").expect("write a.yaml");
+        std::fs::write(dir.join("b.yaml"), "include: a.yaml\n").expect("write b.yaml");
+
+        let err = super::load_layers(&dir.join("a.yaml"), &mut HashSet::new())
+            .expect_err("cycle should be an error");
+        assert!(err.to_string().contains("include cycle"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_missing_top_level_file_error_does_not_say_included() {
+        let dir = test_dir("missing-top-level");
+
+        let err = super::load_layers(&dir.join("does-not-exist.yaml"), &mut HashSet::new())
+            .expect_err("missing file should be an error");
+        assert!(err.to_string().contains("hierarchy file not found"));
+        assert!(!err.to_string().contains("included file"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_missing_included_file_error_says_included() {
+        let dir = test_dir("missing-include");
+
+        std::fs::write(dir.join("host.yaml"), "include: missing.yaml\n").expect("write host.yaml");
+
+        let err = super::load_layers(&dir.join("host.yaml"), &mut HashSet::new())
+            .expect_err("missing include should be an error");
+        assert!(err.to_string().contains("included file is missing"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -0,0 +1,27 @@
+//! Command-line options.
+
+use crate::{Config, DiskState};
+use failure::Error;
+
+/// Parsed command-line options.
+#[derive(Debug, Default)]
+pub struct Opts {
+    /// Load, migrate, and rewrite the state file, then exit without running
+    /// any stages.
+    pub migrate_state: bool,
+}
+
+impl Opts {
+    /// Run `--migrate-state` if it was requested.
+    ///
+    /// Returns `true` if this handled the request, meaning the caller should
+    /// exit rather than go on to run stages.
+    pub fn run_migrate_state(&self, config: &Config) -> Result<bool, Error> {
+        if !self.migrate_state {
+            return Ok(false);
+        }
+
+        DiskState::migrate_file(&config.state_path())?;
+        Ok(true)
+    }
+}
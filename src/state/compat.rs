@@ -0,0 +1,134 @@
+//! Migrations between on-disk `DiskState` schema versions.
+//!
+//! Each migration is a small, ordered function named `migrate_N_to_{N+1}`
+//! that transforms a `DiskState` one version forward. `migrate` runs
+//! whichever of these are needed to bring a loaded state up to
+//! `CURRENT_VERSION` before it's converted into a `State`.
+
+use super::DiskState;
+use failure::{bail, Error};
+
+/// The current on-disk schema version.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Upgrade `state` to `CURRENT_VERSION`, running each migration in order.
+pub fn migrate(mut state: DiskState) -> Result<DiskState, Error> {
+    if state.version > CURRENT_VERSION {
+        bail!(
+            "state file is version {}, but this build of quickcfg only understands up to {}; refusing to load it to avoid corrupting it",
+            state.version,
+            CURRENT_VERSION
+        );
+    }
+
+    if state.version < 1 {
+        state = migrate_0_to_1(state);
+    }
+
+    if state.version < 2 {
+        state = migrate_1_to_2(state);
+    }
+
+    Ok(state)
+}
+
+/// Legacy state files predate the `version` field entirely; everything else
+/// about the layout is unchanged, so this only stamps the version.
+fn migrate_0_to_1(mut state: DiskState) -> DiskState {
+    state.version = 1;
+    state
+}
+
+/// Version 1 stored hash digests as opaque `FxHasher64` output; version 2
+/// switched to a portable, stable digest. The two aren't comparable, so old
+/// entries deserialize with an empty `hash` (see `Hashed`'s `Deserialize`
+/// impl) and are dropped here, letting them recompute on next use rather
+/// than being misread as fresh.
+fn migrate_1_to_2(mut state: DiskState) -> DiskState {
+    state.hashes.retain(|_, hashed| !hashed.hash.is_empty());
+    state.version = 2;
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Hashed;
+    use std::time::SystemTime;
+
+    fn hashed(hash: &str) -> Hashed {
+        Hashed {
+            hash: hash.to_string(),
+            updated: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_migrate_0_to_1_only_stamps_the_version() {
+        let state = DiskState {
+            version: 0,
+            ..DiskState::default()
+        };
+
+        let migrated = migrate_0_to_1(state);
+        assert_eq!(migrated.version, 1);
+    }
+
+    #[test]
+    fn test_migrate_1_to_2_drops_legacy_entries_keeps_real_digests() {
+        let mut state = DiskState {
+            version: 1,
+            ..DiskState::default()
+        };
+        state.hashes.insert("legacy".to_string(), hashed(""));
+        state.hashes.insert("current".to_string(), hashed("abcd"));
+
+        let migrated = migrate_1_to_2(state);
+
+        assert_eq!(migrated.version, 2);
+        assert_eq!(migrated.hashes.len(), 1);
+        assert!(migrated.hashes.contains_key("current"));
+        assert!(!migrated.hashes.contains_key("legacy"));
+    }
+
+    #[test]
+    fn test_migrate_runs_every_step_from_scratch() {
+        let mut state = DiskState {
+            version: 0,
+            ..DiskState::default()
+        };
+        state.hashes.insert("legacy".to_string(), hashed(""));
+        state.hashes.insert("current".to_string(), hashed("abcd"));
+
+        let migrated = migrate(state).expect("migrate from version 0");
+
+        assert_eq!(migrated.version, CURRENT_VERSION);
+        assert_eq!(migrated.hashes.len(), 1);
+        assert!(migrated.hashes.contains_key("current"));
+    }
+
+    #[test]
+    fn test_migrate_is_a_no_op_already_at_current_version() {
+        let mut state = DiskState {
+            version: CURRENT_VERSION,
+            ..DiskState::default()
+        };
+        state.hashes.insert("current".to_string(), hashed("abcd"));
+
+        let migrated = migrate(state).expect("migrate at current version");
+
+        assert_eq!(migrated.version, CURRENT_VERSION);
+        assert_eq!(migrated.hashes.len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_rejects_a_version_newer_than_we_understand() {
+        let state = DiskState {
+            version: CURRENT_VERSION + 1,
+            ..DiskState::default()
+        };
+
+        let err = migrate(state).expect_err("future version must be rejected");
+        assert!(err.to_string().contains("only understands up to"));
+    }
+}
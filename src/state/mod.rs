@@ -0,0 +1,412 @@
+//! Model for state file.
+
+mod compat;
+
+use failure::Error;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::Path;
+use std::time::SystemTime;
+
+pub use self::compat::CURRENT_VERSION;
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Hashed {
+    /// The last calculated digest, as a lowercase hex string.
+    pub hash: String,
+    /// When it was last updated.
+    pub updated: SystemTime,
+}
+
+impl<'de> serde::Deserialize<'de> for Hashed {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Version 1 state files stored the `FxHasher64` output of the hash
+        // as a plain integer. It isn't comparable to the digest we store
+        // now, so such entries deserialize with an empty `hash`, which
+        // never matches a real digest and is dropped during migration.
+        #[derive(serde_derive::Deserialize)]
+        #[serde(untagged)]
+        enum HashValue {
+            Hex(String),
+            Legacy(u64),
+        }
+
+        #[derive(serde_derive::Deserialize)]
+        struct Raw {
+            hash: HashValue,
+            updated: SystemTime,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+
+        let hash = match raw.hash {
+            HashValue::Hex(hash) => hash,
+            HashValue::Legacy(_) => String::new(),
+        };
+
+        Ok(Hashed {
+            hash,
+            updated: raw.updated,
+        })
+    }
+}
+
+/// The way the state is serialized.
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+pub struct DiskState {
+    /// Schema version of this file. Absent in files written before
+    /// versioning was introduced, which are treated as version `0`.
+    #[serde(default)]
+    pub version: u32,
+    /// Last time git was updated.
+    #[serde(default)]
+    pub last_update: BTreeMap<String, SystemTime>,
+    /// Things that should only happen once.
+    #[serde(default)]
+    pub once: BTreeMap<String, SystemTime>,
+    #[serde(default)]
+    pub hashes: BTreeMap<String, Hashed>,
+}
+
+impl DiskState {
+    /// Convert into a state, migrating the on-disk format to the current
+    /// version first.
+    ///
+    /// If migration actually changed the version, the returned `State` is
+    /// marked dirty so the next `serialize` call persists the upgraded
+    /// format, even if nothing else about the state changed.
+    pub fn to_state(self) -> Result<State, Error> {
+        let from_version = self.version;
+        let migrated = compat::migrate(self)?;
+        let upgraded = migrated.version != from_version;
+
+        Ok(State {
+            dirty: upgraded,
+            last_update: migrated.last_update,
+            once: migrated.once,
+            hashes: migrated.hashes,
+        })
+    }
+
+    /// Load, migrate, and rewrite the state file at `path` without running
+    /// any stages.
+    ///
+    /// This backs the `--migrate-state` mode in `opts`, for users who want
+    /// to upgrade explicitly rather than relying on the next normal run.
+    pub fn migrate_file(path: &Path) -> Result<(), Error> {
+        use crate::file_operations::{Load, Save};
+
+        let state: State = DiskState::load(path)?.to_state()?;
+
+        // Always write back the already-loaded data at the current version,
+        // regardless of `state.dirty` - unlike a normal run, `--migrate-state`
+        // must persist even a state that was already up to date.
+        DiskState {
+            version: CURRENT_VERSION,
+            last_update: state.last_update,
+            once: state.once,
+            hashes: state.hashes,
+        }
+        .save(path)
+    }
+}
+
+/// State model.
+/// This keeps track of any changes with the dirty flag, which is an indication whether it should
+/// be serialized or not.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct State {
+    pub dirty: bool,
+    /// Last time git was updated.
+    pub last_update: BTreeMap<String, SystemTime>,
+    /// Things that should only happen once.
+    pub once: BTreeMap<String, SystemTime>,
+    /// Things that have been tested against a hash.
+    pub hashes: BTreeMap<String, Hashed>,
+}
+
+impl State {
+    /// Get the last update timestamp for the given thing named `name`.
+    pub fn last_update<'a>(&'a self, name: &str) -> Option<&'a SystemTime> {
+        self.last_update.get(name)
+    }
+
+    /// Touch the thing with the given name.
+    pub fn touch(&mut self, name: &str) {
+        self.dirty = true;
+        self.last_update.insert(name.to_string(), SystemTime::now());
+    }
+
+    /// Check if the given ID has run once.
+    pub fn has_run_once(&self, id: &str) -> bool {
+        self.once.contains_key(id)
+    }
+
+    /// Mark that something has happened once.
+    pub fn touch_once(&mut self, id: &str) {
+        self.dirty = true;
+        self.once.insert(id.to_string(), SystemTime::now());
+    }
+
+    /// Check whether the stored digest for `id` matches the digest of `hash`.
+    pub fn is_hash_fresh<H: Hash>(&self, id: &str, hash: H) -> Result<bool, Error> {
+        let hashed = match self.hashes.get(id) {
+            Some(hashed) => hashed,
+            None => return Ok(false),
+        };
+
+        Ok(hashed.hash == digest_of(hash))
+    }
+
+    /// Store the digest of `hash` under `id`.
+    pub fn touch_hash<H: Hash>(&mut self, id: &str, hash: H) -> Result<(), Error> {
+        self.dirty = true;
+
+        self.hashes.insert(
+            id.to_string(),
+            Hashed {
+                hash: digest_of(hash),
+                updated: SystemTime::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Check whether the stored digest for `id` matches the current
+    /// contents of the file at `path`.
+    pub fn is_file_hash_fresh(&self, id: &str, path: &Path) -> Result<bool, Error> {
+        let hashed = match self.hashes.get(id) {
+            Some(hashed) => hashed,
+            None => return Ok(false),
+        };
+
+        Ok(hashed.hash == digest_file(path)?)
+    }
+
+    /// Store the digest of the file at `path` under `id`, reading it in
+    /// chunks so large templated files don't have to be fully materialized
+    /// in memory first.
+    pub fn touch_file_hash(&mut self, id: &str, path: &Path) -> Result<(), Error> {
+        let hash = digest_file(path)?;
+
+        self.dirty = true;
+
+        self.hashes.insert(
+            id.to_string(),
+            Hashed {
+                hash,
+                updated: SystemTime::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Extend this state with another.
+    pub fn extend(&mut self, other: State) {
+        // nothing to extend.
+        if !other.dirty {
+            return;
+        }
+
+        self.dirty = true;
+        self.last_update.extend(other.last_update);
+        self.once.extend(other.once);
+    }
+
+    /// Serialize the state, returning `None` unless it is dirty.
+    pub fn serialize(self) -> Option<DiskState> {
+        if !self.dirty {
+            return None;
+        }
+
+        Some(DiskState {
+            version: CURRENT_VERSION,
+            last_update: self.last_update,
+            once: self.once,
+            hashes: self.hashes,
+        })
+    }
+}
+
+/// A `Hasher` that just collects the bytes it's given, so that a `Hash` impl
+/// can feed a canonical byte sequence into a real digest instead of the
+/// result depending on a particular `Hasher` algorithm's write-order quirks.
+///
+/// The width-specific `write_*` methods must be overridden too: `Hasher`'s
+/// default implementations for them forward to `write` with the value's
+/// native-endian byte representation (pointer-width-sized for `usize` and
+/// `isize`), which would make the resulting digest depend on the hashing
+/// machine's architecture. We widen every integer up to 64 bits to a fixed
+/// `u64` encoded little-endian instead, and encode 128-bit integers
+/// little-endian at their full width, so the same logical value always
+/// produces the same bytes.
+#[derive(Default)]
+struct ByteCollector(Vec<u8>);
+
+impl Hasher for ByteCollector {
+    fn finish(&self) -> u64 {
+        unreachable!("ByteCollector only collects bytes to digest, it never finishes")
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0.extend_from_slice(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.0.extend_from_slice(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_u8(i as u8);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// Digest any `Hash` value into a lowercase hex string.
+fn digest_of<H: Hash>(value: H) -> String {
+    let mut collector = ByteCollector::default();
+    value.hash(&mut collector);
+
+    let mut hasher = Sha256::new();
+    hasher.update(&collector.0);
+    to_hex(&hasher.finalize())
+}
+
+/// Digest the contents of a file, streaming it in chunks.
+fn digest_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+
+    loop {
+        let n = file.read(&mut buf)?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        write!(&mut out, "{:02x}", byte).expect("writing to a String never fails");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_u64_hash_deserializes_to_empty_hash() {
+        let hashed: Hashed = serde_json::from_str(
+            r#"{"hash": 18446744073709551615, "updated": {"secs_since_epoch": 0, "nanos_since_epoch": 0}}"#,
+        )
+        .expect("legacy hash value must still deserialize");
+
+        assert_eq!(hashed.hash, "");
+    }
+
+    #[test]
+    fn test_current_hex_hash_deserializes_unchanged() {
+        let hashed: Hashed = serde_json::from_str(
+            r#"{"hash": "abcd", "updated": {"secs_since_epoch": 0, "nanos_since_epoch": 0}}"#,
+        )
+        .expect("hex hash value must deserialize");
+
+        assert_eq!(hashed.hash, "abcd");
+    }
+
+    #[test]
+    fn test_to_state_marks_dirty_when_migration_upgrades_the_version() {
+        let mut disk = DiskState {
+            version: 0,
+            ..DiskState::default()
+        };
+        disk.hashes.insert(
+            "legacy".to_string(),
+            Hashed {
+                hash: String::new(),
+                updated: SystemTime::now(),
+            },
+        );
+
+        let state = disk.to_state().expect("migrate a legacy state");
+
+        assert!(state.dirty, "an upgraded version must be marked dirty");
+        assert!(
+            !state.hashes.contains_key("legacy"),
+            "dropped legacy hashes must not resurface in State"
+        );
+    }
+
+    #[test]
+    fn test_to_state_is_clean_when_already_current() {
+        let disk = DiskState {
+            version: CURRENT_VERSION,
+            ..DiskState::default()
+        };
+
+        let state = disk.to_state().expect("state already at current version");
+
+        assert!(!state.dirty, "an already-current state must not be dirty");
+    }
+}